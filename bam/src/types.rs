@@ -1,64 +1,75 @@
 use crate::{hashmap, syntax::*};
 use lazy_static::lazy_static;
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
 use thiserror::Error;
 
 // TODO: Check that variables are only used once
 
 lazy_static! {
     pub static ref BUILTIN_MAP: HashMap<Builtin, MachineType> = hashmap!(<Builtin, MachineType> [
-        Builtin::Add => {   // (Num, Num) -> Num
+        Builtin::Add => {   // forall a: Numeric. (a, a) -> a
             MachineType {
                 var_count: 0,
-                input: Type::Tuple(vec![Type::Num, Type::Num]),
-                output: Type::Num
+                numeric_var_count: 1,
+                input: Type::Tuple(vec![Type::NumVar(0), Type::NumVar(0)]),
+                output: Type::NumVar(0)
             }
         },
-        Builtin::Mul => {   // (Num, Num) -> Num
+        Builtin::Mul => {   // forall a: Numeric. (a, a) -> a
             MachineType {
                 var_count: 0,
-                input: Type::Tuple(vec![Type::Num, Type::Num]),
-                output: Type::Num
+                numeric_var_count: 1,
+                input: Type::Tuple(vec![Type::NumVar(0), Type::NumVar(0)]),
+                output: Type::NumVar(0)
             }
         },
-        Builtin::Mod => {   // (Num, Num) -> Num
+        Builtin::Mod => {   // (Int, Int) -> Int
             MachineType {
                 var_count: 0,
-                input: Type::Tuple(vec![Type::Num, Type::Num]),
-                output: Type::Num
+                numeric_var_count: 0,
+                input: Type::Tuple(vec![Type::Int, Type::Int]),
+                output: Type::Int
             }
         },
-        Builtin::Pow => {   // (Num, Num) -> Num
+        Builtin::Pow => {   // (Float, Float) -> Float
             MachineType {
                 var_count: 0,
-                input: Type::Tuple(vec![Type::Num, Type::Num]),
-                output: Type::Num
+                numeric_var_count: 0,
+                input: Type::Tuple(vec![Type::Float, Type::Float]),
+                output: Type::Float
             }
         },
-        Builtin::Sqrt => {   // Num -> Num
+        Builtin::Sqrt => {   // Float -> Float
             MachineType {
                 var_count: 0,
-                input: Type::Tuple(vec![Type::Num]),
-                output: Type::Num
+                numeric_var_count: 0,
+                input: Type::Tuple(vec![Type::Float]),
+                output: Type::Float
             }
         },
-        Builtin::Gte => {   // (Num, Num) -> Bool
+        Builtin::Gte => {   // forall a: Numeric. (a, a) -> Bool
             MachineType {
                 var_count: 0,
-                input: Type::Tuple(vec![Type::Num, Type::Num]),
+                numeric_var_count: 1,
+                input: Type::Tuple(vec![Type::NumVar(0), Type::NumVar(0)]),
                 output: Type::Bool
             }
         },
-        Builtin::Lt => {   // (Num, Num) -> Bool
+        Builtin::Lt => {   // forall a: Numeric. (a, a) -> Bool
             MachineType {
                 var_count: 0,
-                input: Type::Tuple(vec![Type::Num, Type::Num]),
+                numeric_var_count: 1,
+                input: Type::Tuple(vec![Type::NumVar(0), Type::NumVar(0)]),
                 output: Type::Bool
             }
         },
         Builtin::Eq => {   // forall a. (a, a) -> Bool
             MachineType {
                 var_count: 1,
+                numeric_var_count: 0,
                 input: Type::Tuple(vec![Type::TyVar(0), Type::TyVar(0)]),
                 output: Type::Bool
             }
@@ -66,6 +77,7 @@ lazy_static! {
         Builtin::Dup2 => {  // forall a. a -> (a, a)
             MachineType {
                 var_count: 1,
+                numeric_var_count: 0,
                 input: Type::TyVar(0),
                 output: Type::Tuple(vec![Type::TyVar(0), Type::TyVar(0)])
             }
@@ -73,6 +85,7 @@ lazy_static! {
         Builtin::Dup3 => {  // forall a. a -> (a, a, a)
             MachineType {
                 var_count: 1,
+                numeric_var_count: 0,
                 input: Type::TyVar(0),
                 output: Type::Tuple(vec![Type::TyVar(0), Type::TyVar(0), Type::TyVar(0)])
             }
@@ -80,6 +93,7 @@ lazy_static! {
         Builtin::Print => { // forall a. a -> a
             MachineType {
                 var_count: 1,
+                numeric_var_count: 0,
                 input: Type::TyVar(0),
                 output: Type::TyVar(0)
             }
@@ -89,18 +103,24 @@ lazy_static! {
 
 #[derive(Debug, Clone)]
 pub enum Type {
-    Num,
+    Int,
+    Float,
     Bool,
     String,
     Tuple(Vec<Type>),
     TyVar(usize),
+    // A type variable in a `MachineType` scheme for the numeric types (`Int` or `Float`).
+    // `instantiate` turns this into a fresh `UnifVar` flagged in the `UnifTable` as belonging
+    // to the numeric class; see `UnifTable::numeric_vars` for what that flag does.
+    NumVar(usize),
     UnifVar(usize),
 }
 
 impl Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let message = match self {
-            Self::Num => "num".to_owned(),
+            Self::Int => "int".to_owned(),
+            Self::Float => "float".to_owned(),
             Self::Bool => "bool".to_owned(),
             Self::String => "string".to_owned(),
             Self::Tuple(value) => format!(
@@ -110,6 +130,7 @@ impl Display for Type {
                     .fold("(".to_owned(), |acc, ty| format!("{acc} {ty}"))
             ),
             Self::TyVar(value) => format!("Type Var: {value}"),
+            Self::NumVar(value) => format!("Numeric Var: {value}"),
             Self::UnifVar(value) => format!("Uniform Var: {value}"),
         };
 
@@ -119,13 +140,31 @@ impl Display for Type {
 
 #[derive(Debug, Clone, Error)]
 pub enum TypeError {
-    #[error("cannot unify {0} with {1}")]
-    CannotUnify(Type, Type),
+    #[error("cannot unify {0} with {1} at {2}")]
+    CannotUnify(Type, Type, Span),
+    #[error("infinite type: Uniform Var: {0} occurs in {1} at {2}")]
+    InfiniteType(usize, Type, Span),
+    #[error("unbound variable '{0}' at {1}")]
+    UnboundVariable(String, Span),
+    #[error("unbound machine '{0}' at {1}")]
+    UnboundMachine(String, Span),
+    #[error("expected a tuple of {expected} elements but found {found} at {span}")]
+    TupleArityMismatch {
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    // Should be unreachable: every `Builtin` variant is expected to have an entry in
+    // `BUILTIN_MAP`. Kept as a diagnostic rather than a panic so a missing entry is reported
+    // like any other type error instead of crashing the checker.
+    #[error("builtin {0:?} at {1} has no registered type")]
+    MissingBuiltinType(Builtin, Span),
 }
 
 #[derive(Debug, Clone)]
 pub struct MachineType {
     var_count: usize,
+    numeric_var_count: usize,
     input: Type,
     output: Type,
 }
@@ -136,72 +175,363 @@ struct GlobalTypeEnv {
 
 struct LocalTypeEnv {
     var_types: HashMap<String, Type>,
-    unification_constraints: Vec<(Type, Type)>,
-    last_unification_var: usize,
+    // Each constraint carries the span of the expression that produced it, so a failed
+    // `unify` can point back at the offending source location instead of just the two types.
+    unification_constraints: Vec<(Type, Type, Span)>,
+    table: UnifTable,
 }
 
-pub fn check(program: &Program) -> Result<(), TypeError> {
+// A union-find table mapping each `UnifVar(id)` to either an unbound class (with a rank used
+// to keep unions shallow) or a `Type` bound to the whole class. Binding a variable binds the
+// root of its class, so `find` plus path compression keeps every lookup close to O(1).
+#[derive(Debug, Clone, Default)]
+struct UnifTable {
+    nodes: Vec<UnifNode>,
+    // Roots of classes flagged as numeric (`Int`/`Float`). Kept up to date by `union_vars`,
+    // so looking this up always requires `find`-ing the current root first. The flag only
+    // makes conflicting `Int`/`Float` bindings of a class resolve to their join; binding a
+    // flagged class to a non-numeric type (e.g. `String`) is not rejected.
+    numeric_vars: HashSet<usize>,
+    // Spans where an `Int` and a `Float` were unified, each recorded as a widening of the
+    // `Int` to `Float` for codegen to insert the matching conversion.
+    coercions: Vec<Span>,
+}
+
+#[derive(Debug, Clone)]
+enum UnifNode {
+    Root { rank: usize, value: Option<Type> },
+    Child(usize),
+}
+
+impl UnifTable {
+    fn new_var(&mut self) -> usize {
+        let id = self.nodes.len();
+        self.nodes.push(UnifNode::Root {
+            rank: 0,
+            value: None,
+        });
+        id
+    }
+
+    // Finds the representative of `var`'s class, compressing the path to it along the way.
+    fn find(&mut self, var: usize) -> usize {
+        match self.nodes[var] {
+            UnifNode::Root { .. } => var,
+            UnifNode::Child(parent) => {
+                let root = self.find(parent);
+                self.nodes[var] = UnifNode::Child(root);
+                root
+            }
+        }
+    }
+
+    // Returns the `Type` bound to `var`'s class, if any. `var` must already be a root.
+    fn value(&self, var: usize) -> Option<Type> {
+        match &self.nodes[var] {
+            UnifNode::Root { value, .. } => value.clone(),
+            UnifNode::Child(_) => unreachable!("value() must be called with a root"),
+        }
+    }
+
+    // Binds `var`'s class to `ty`. `var` must already be a root.
+    fn bind(&mut self, var: usize, ty: Type) {
+        match &mut self.nodes[var] {
+            UnifNode::Root { value, .. } => *value = Some(ty),
+            UnifNode::Child(_) => unreachable!("bind() must be called with a root"),
+        }
+    }
+
+    // Merges two classes by rank and returns the surviving root, which keeps its own bound
+    // value (the other root's value is dropped). `a` and `b` must already be roots and must
+    // not share a class.
+    fn union_vars(&mut self, a: usize, b: usize) -> usize {
+        let (rank_a, rank_b) = match (&self.nodes[a], &self.nodes[b]) {
+            (UnifNode::Root { rank: ra, .. }, UnifNode::Root { rank: rb, .. }) => (*ra, *rb),
+            _ => unreachable!("union_vars() must be called with two distinct roots"),
+        };
+
+        let survivor = if rank_a < rank_b {
+            self.nodes[a] = UnifNode::Child(b);
+            b
+        } else if rank_a > rank_b {
+            self.nodes[b] = UnifNode::Child(a);
+            a
+        } else {
+            self.nodes[b] = UnifNode::Child(a);
+            if let UnifNode::Root { rank, .. } = &mut self.nodes[a] {
+                *rank += 1;
+            }
+            a
+        };
+
+        // `|` (not `||`) so both sides are always removed, regardless of which was numeric.
+        if self.numeric_vars.remove(&a) | self.numeric_vars.remove(&b) {
+            self.numeric_vars.insert(survivor);
+        }
+
+        survivor
+    }
+
+    // Flags `var`'s class as constrained to the numeric types. `var` must already be a root.
+    fn mark_numeric(&mut self, var: usize) {
+        self.numeric_vars.insert(var);
+    }
+
+    // Whether `var`'s class is constrained to the numeric types.
+    fn is_numeric(&mut self, var: usize) -> bool {
+        let root = self.find(var);
+        self.numeric_vars.contains(&root)
+    }
+
+    fn record_coercion(&mut self, span: Span) {
+        self.coercions.push(span);
+    }
+
+    pub fn coercions(&self) -> &[Span] {
+        &self.coercions
+    }
+
+    // Resolves `ty` one level: a bound `UnifVar` becomes its bound `Type`, an unbound one
+    // becomes `Type::UnifVar` of its representative. Does not look inside tuples.
+    fn resolve_shallow(&mut self, ty: &Type) -> Type {
+        match ty {
+            Type::UnifVar(a) => {
+                let root = self.find(*a);
+                self.value(root).unwrap_or(Type::UnifVar(root))
+            }
+            _ => ty.clone(),
+        }
+    }
+
+    // Fully resolves `ty`, recursing into tuples and following chains of bound variables.
+    fn resolve_deep(&mut self, ty: &Type) -> Type {
+        match self.resolve_shallow(ty) {
+            Type::Tuple(tys) => Type::Tuple(tys.iter().map(|ty| self.resolve_deep(ty)).collect()),
+            ty => ty,
+        }
+    }
+}
+
+// Returns the spans of every implicit `Int` -> `Float` coercion inserted while checking
+// `program`, so codegen can insert the matching conversions.
+pub fn check(program: &Program) -> Result<Vec<Span>, TypeError> {
     let mut global_env = GlobalTypeEnv {
         machine_types: HashMap::new(),
     };
-    for machine in &program.machines {
-        check_machine_def(&mut global_env, machine)?;
+    let mut coercions = Vec::new();
+    // Machines are checked one strongly-connected component at a time, in the order Tarjan's
+    // algorithm produces them: every machine a component calls is itself checked (and
+    // generalized) in an earlier component, so instantiating a call into it is always valid,
+    // and mutual/self recursion within a component is handled by sharing one inference pass
+    // across the whole component.
+    for group in machine_sccs(program) {
+        coercions.extend(check_machine_group(&mut global_env, program, &group)?);
     }
-    Ok(())
+    Ok(coercions)
 }
 
-fn check_machine_def(
+fn check_machine_group(
     global_env: &mut GlobalTypeEnv,
-    machine: &Definition,
-) -> Result<(), TypeError> {
+    program: &Program,
+    group: &[String],
+) -> Result<Vec<Span>, TypeError> {
     let mut local_env = LocalTypeEnv {
         var_types: HashMap::new(),
         unification_constraints: Vec::new(),
-        last_unification_var: 0,
+        table: UnifTable::default(),
     };
-    // The type of the machine itself is unknown right now, but we need it to check recursive calls
-    // so we construct a machine type out of unification variables
-    let machine_type_input = new_unif_var(&mut local_env);
-    let machine_type_output = new_unif_var(&mut local_env);
-    let machine_type = MachineType {
-        var_count: 0,
-        input: machine_type_input,
-        output: machine_type_output.clone(),
+
+    // The type of every machine in the component is unknown right now, but we need it to
+    // check calls within the component (including self- and mutual recursion), so we
+    // construct a machine type out of unification variables for each of them up front.
+    let machine_types: HashMap<String, MachineType> = group
+        .iter()
+        .map(|name| {
+            let machine_type = MachineType {
+                var_count: 0,
+                numeric_var_count: 0,
+                input: new_unif_var(&mut local_env),
+                output: new_unif_var(&mut local_env),
+            };
+            global_env
+                .machine_types
+                .insert(name.clone(), machine_type.clone());
+            (name.clone(), machine_type)
+        })
+        .collect();
+
+    for name in group {
+        let machine = program
+            .machines
+            .iter()
+            .find(|machine| &machine.name == name)
+            .expect("every machine in a component comes from program.machines");
+
+        // Variable bindings don't cross machine bodies, so each member of the component
+        // starts from a clean slate; the unification table and constraints, however, are
+        // shared for the whole component so cross-references unify against the same vars.
+        local_env.var_types.clear();
+
+        for statement in &machine.body {
+            check_statement(global_env, &mut local_env, statement)?;
+        }
+
+        let real_output_type = infer_stream(global_env, &mut local_env, &machine.result)?;
+        let machine_type = &machine_types[name];
+        local_env.unification_constraints.push((
+            machine_type.output.clone(),
+            real_output_type,
+            machine.result.span(),
+        ));
+    }
+
+    let mut subst = unify(&mut local_env)?;
+
+    for name in group {
+        let generalized_machine_type = generalize(&mut subst, machine_types[name].clone());
+        global_env
+            .machine_types
+            .insert(name.clone(), generalized_machine_type);
+    }
+
+    Ok(subst.coercions().to_vec())
+}
+
+// Collects the names of machines directly referenced (via `Machine::Var`) from each machine
+// definition in `program`, keyed by the referencing machine's name. References to a name that
+// isn't actually defined in `program.machines` are dropped rather than turned into a graph
+// edge: they aren't machines Tarjan needs to order, and leaving them out lets `infer_stream`'s
+// `UnboundMachine` check (not a graph traversal) be the thing that reports them.
+fn machine_call_graph(program: &Program) -> HashMap<String, Vec<String>> {
+    let defined_names: HashSet<&str> = program
+        .machines
+        .iter()
+        .map(|machine| machine.name.as_str())
+        .collect();
+
+    program
+        .machines
+        .iter()
+        .map(|machine| {
+            let mut refs = Vec::new();
+            for statement in &machine.body {
+                match statement {
+                    Statement::Consume(stream) | Statement::Let(_, stream) => {
+                        collect_machine_refs(stream, &mut refs)
+                    }
+                }
+            }
+            collect_machine_refs(&machine.result, &mut refs);
+            refs.retain(|name| defined_names.contains(name.as_str()));
+            (machine.name.clone(), refs)
+        })
+        .collect()
+}
+
+fn collect_machine_refs(stream: &Stream, refs: &mut Vec<String>) {
+    match stream {
+        Stream::Var(_) | Stream::Const(_) => (),
+        Stream::Pipe(stream, machine) => {
+            collect_machine_refs(stream, refs);
+            if let Machine::Var(name) = &**machine {
+                refs.push(name.clone());
+            }
+        }
+        Stream::Zip(streams) => {
+            for stream in streams {
+                collect_machine_refs(stream, refs)
+            }
+        }
+        Stream::Cond(condition, then, else_) => {
+            collect_machine_refs(condition, refs);
+            collect_machine_refs(then, refs);
+            collect_machine_refs(else_, refs);
+        }
+        Stream::Limit(stream, _) => collect_machine_refs(stream, refs),
+        Stream::Unzip(stream, _) => collect_machine_refs(stream, refs),
+    }
+}
+
+// Groups `program`'s machines into strongly-connected components of the call graph, using
+// Tarjan's algorithm. The components are returned in the order Tarjan completes them, which
+// is already reverse-topological: a component is only finished once every component it calls
+// into has been finished first.
+fn machine_sccs(program: &Program) -> Vec<Vec<String>> {
+    let graph = machine_call_graph(program);
+    let mut state = TarjanState {
+        graph: &graph,
+        index: HashMap::new(),
+        lowlink: HashMap::new(),
+        on_stack: HashMap::new(),
+        stack: Vec::new(),
+        next_index: 0,
+        sccs: Vec::new(),
     };
-    global_env
-        .machine_types
-        .insert(machine.name.clone(), machine_type.clone());
 
-    for statement in &machine.body {
-        check_statement(global_env, &mut local_env, statement);
+    for machine in &program.machines {
+        if !state.index.contains_key(&machine.name) {
+            tarjan_strongconnect(&mut state, &machine.name);
+        }
     }
 
-    let real_output_type = infer_stream(global_env, &mut local_env, &machine.result);
-    local_env
-        .unification_constraints
-        .push((machine_type_output, real_output_type));
+    state.sccs
+}
 
-    let subst = unify(&local_env)?;
+struct TarjanState<'a> {
+    graph: &'a HashMap<String, Vec<String>>,
+    index: HashMap<String, usize>,
+    lowlink: HashMap<String, usize>,
+    on_stack: HashMap<String, bool>,
+    stack: Vec<String>,
+    next_index: usize,
+    sccs: Vec<Vec<String>>,
+}
 
-    let generalized_machine_type = generalize(&subst, machine_type);
-    global_env
-        .machine_types
-        .insert(machine.name.clone(), generalized_machine_type);
+fn tarjan_strongconnect(state: &mut TarjanState, v: &str) {
+    state.index.insert(v.to_owned(), state.next_index);
+    state.lowlink.insert(v.to_owned(), state.next_index);
+    state.next_index += 1;
+    state.stack.push(v.to_owned());
+    state.on_stack.insert(v.to_owned(), true);
+
+    let neighbors = state.graph.get(v).cloned().unwrap_or_default();
+    for w in neighbors {
+        if !state.index.contains_key(&w) {
+            tarjan_strongconnect(state, &w);
+            let new_low = state.lowlink[v].min(state.lowlink[&w]);
+            state.lowlink.insert(v.to_owned(), new_low);
+        } else if *state.on_stack.get(&w).unwrap_or(&false) {
+            let new_low = state.lowlink[v].min(state.index[&w]);
+            state.lowlink.insert(v.to_owned(), new_low);
+        }
+    }
 
-    Ok(())
+    if state.lowlink[v] == state.index[v] {
+        let mut component = Vec::new();
+        loop {
+            let w = state.stack.pop().expect("v's own index keeps the stack non-empty here");
+            state.on_stack.insert(w.clone(), false);
+            let is_v = w == v;
+            component.push(w);
+            if is_v {
+                break;
+            }
+        }
+        state.sccs.push(component);
+    }
 }
 
 fn check_statement(
     global_env: &mut GlobalTypeEnv,
     local_env: &mut LocalTypeEnv,
     statement: &Statement,
-) {
+) -> Result<(), TypeError> {
     match statement {
         Statement::Consume(stream) => {
-            let _ = infer_stream(global_env, local_env, stream);
+            let _ = infer_stream(global_env, local_env, stream)?;
         }
         Statement::Let(vars, stream) => {
-            let stream_ty = infer_stream(global_env, local_env, stream);
+            let stream_ty = infer_stream(global_env, local_env, stream)?;
 
             if vars.len() == 1 {
                 // If we only bind a single variable, there is no destructuring involved
@@ -221,85 +551,103 @@ fn check_statement(
                     Type::Tuple(tuple_tys.into_iter().map(|(_, ty)| ty).collect());
                 local_env
                     .unification_constraints
-                    .push((variable_tuple_ty, stream_ty))
+                    .push((variable_tuple_ty, stream_ty, stream.span()))
             }
         }
     }
+    Ok(())
 }
 
 fn infer_stream(
     global_env: &mut GlobalTypeEnv,
     local_env: &mut LocalTypeEnv,
     stream: &Stream,
-) -> Type {
+) -> Result<Type, TypeError> {
     match stream {
         Stream::Var(name) => match local_env.var_types.get(name) {
-            Some(ty) => ty.clone(),
-            None => panic!(
-                "infer_stream: Unbound variable found during type checking: '{}'",
-                name
-            ),
+            Some(ty) => Ok(ty.clone()),
+            None => Err(TypeError::UnboundVariable(name.clone(), stream.span())),
         },
         Stream::Const(Value::Null) => {
             // 'null' can have any type, so we treat it like 'forall a. a'
-            new_unif_var(local_env)
+            Ok(new_unif_var(local_env))
         }
-        Stream::Const(Value::Num(_)) => Type::Num,
-        Stream::Const(Value::Str(_)) => Type::String,
-        Stream::Const(Value::Bool(_)) => Type::Bool,
+        Stream::Const(Value::Num(_)) => {
+            // A numeric literal could be either an 'int' or a 'float', so we type it as a
+            // fresh numeric-class variable and let context (e.g. a builtin it's piped into)
+            // pin down which one, the same way 'null' above defers to a plain type variable.
+            let var = new_unif_var(local_env);
+            if let Type::UnifVar(id) = var {
+                local_env.table.mark_numeric(id);
+            }
+            Ok(var)
+        }
+        Stream::Const(Value::Str(_)) => Ok(Type::String),
+        Stream::Const(Value::Bool(_)) => Ok(Type::Bool),
         Stream::Const(Value::Tuple(_)) => {
             panic!("infer_stream: Tuple constants should not be able to appear in source files")
         }
 
         Stream::Pipe(stream, machine) => {
-            let stream_ty = infer_stream(global_env, local_env, stream);
+            let stream_ty = infer_stream(global_env, local_env, stream)?;
 
             let machine_ty = match &**machine {
                 Machine::Var(machine_name) => match global_env.machine_types.get(machine_name) {
                     Some(ty) => ty.clone(),
-                    None => panic!(
-                        "infer_stream: Unbound machine found during type checking: '{}'",
-                        machine_name
-                    ),
+                    None => {
+                        return Err(TypeError::UnboundMachine(
+                            machine_name.clone(),
+                            machine.span(),
+                        ))
+                    }
+                },
+                Machine::Builtin(builtin) => match get_builtin_ty(builtin) {
+                    Some(ty) => ty,
+                    None => {
+                        return Err(TypeError::MissingBuiltinType(
+                            builtin.clone(),
+                            machine.span(),
+                        ))
+                    }
                 },
-                Machine::Builtin(builtin) => get_builtin_ty(builtin)
-                    .unwrap_or_else(|| panic!("{builtin:#?} not found in BUILTIN_MAP")),
                 Machine::Defined(_, _) => panic!(
                     "infer_stream: Machine::Defined should not be able to appear in source files"
                 ),
             };
             let machine_ty = instantiate(local_env, machine_ty);
 
-            local_env
-                .unification_constraints
-                .push((machine_ty.input.clone(), stream_ty));
-            machine_ty.output
+            local_env.unification_constraints.push((
+                machine_ty.input.clone(),
+                stream_ty,
+                stream.span(),
+            ));
+            Ok(machine_ty.output)
         }
 
         Stream::Zip(streams) => {
             let stream_tys = streams
                 .iter()
                 .map(|stream| infer_stream(global_env, local_env, stream))
-                .collect();
-            Type::Tuple(stream_tys)
+                .collect::<Result<_, _>>()?;
+            Ok(Type::Tuple(stream_tys))
         }
 
         Stream::Cond(condition, then, else_) => {
-            let condition_ty = infer_stream(global_env, local_env, condition);
+            let condition_ty = infer_stream(global_env, local_env, condition)?;
             local_env
                 .unification_constraints
-                .push((condition_ty, Type::Bool));
+                .push((condition_ty, Type::Bool, condition.span()));
 
-            let then_ty = infer_stream(global_env, local_env, then);
-            let else_ty = infer_stream(global_env, local_env, else_);
+            let then_ty = infer_stream(global_env, local_env, then)?;
+            let else_ty = infer_stream(global_env, local_env, else_)?;
 
             local_env
                 .unification_constraints
-                .push((then_ty.clone(), else_ty));
+                .push((then_ty.clone(), else_ty, stream.span()));
 
             // Since we made sure the types of the 'then' and 'else' expressions are
             // equivalent, it doesn't matter which one we return here. We arbitrarily pick the 'then' branch.
-            then_ty
+            Ok(then_ty)
         }
         Stream::Limit(stream, _) => infer_stream(global_env, local_env, stream),
 
@@ -314,25 +662,43 @@ fn get_builtin_ty(builtin: &Builtin) -> Option<MachineType> {
 }
 
 fn new_unif_var(local_env: &mut LocalTypeEnv) -> Type {
-    let var_id = local_env.last_unification_var;
-    local_env.last_unification_var += 1;
-    Type::UnifVar(var_id)
+    Type::UnifVar(local_env.table.new_var())
 }
 
 fn instantiate(local_env: &mut LocalTypeEnv, machine_ty: MachineType) -> MachineType {
     let unif_vars: Vec<_> = (0..machine_ty.var_count)
         .map(|i| (i, new_unif_var(local_env)))
         .collect();
+    // Each numeric-class scheme variable also becomes a fresh `UnifVar`, but flagged in the
+    // table so later unification resolves conflicting `Int`/`Float` occurrences of it to
+    // their join instead of rejecting them outright.
+    let numeric_vars: Vec<_> = (0..machine_ty.numeric_var_count)
+        .map(|i| {
+            let var = new_unif_var(local_env);
+            if let Type::UnifVar(id) = var {
+                local_env.table.mark_numeric(id);
+            }
+            (i, var)
+        })
+        .collect();
 
     let input = unif_vars
         .iter()
         .rfold(machine_ty.input, |ty, (i, var)| replace_ty_var(ty, *i, var));
+    let input = numeric_vars
+        .iter()
+        .rfold(input, |ty, (i, var)| replace_num_var(ty, *i, var));
+
     let output = unif_vars.iter().rfold(machine_ty.output, |ty, (i, var)| {
         replace_ty_var(ty, *i, var)
     });
+    let output = numeric_vars
+        .iter()
+        .rfold(output, |ty, (i, var)| replace_num_var(ty, *i, var));
 
     MachineType {
         var_count: 0,
+        numeric_var_count: 0,
         input,
         output,
     }
@@ -340,7 +706,9 @@ fn instantiate(local_env: &mut LocalTypeEnv, machine_ty: MachineType) -> Machine
 
 fn replace_ty_var(ty: Type, var: usize, to_replace: &Type) -> Type {
     match ty {
-        Type::Num | Type::Bool | Type::String | Type::UnifVar(_) => ty,
+        Type::Int | Type::Float | Type::Bool | Type::String | Type::NumVar(_) | Type::UnifVar(_) => {
+            ty
+        }
         Type::TyVar(other) => {
             if other == var {
                 to_replace.clone()
@@ -356,9 +724,31 @@ fn replace_ty_var(ty: Type, var: usize, to_replace: &Type) -> Type {
     }
 }
 
+fn replace_num_var(ty: Type, var: usize, to_replace: &Type) -> Type {
+    match ty {
+        Type::Int | Type::Float | Type::Bool | Type::String | Type::TyVar(_) | Type::UnifVar(_) => {
+            ty
+        }
+        Type::NumVar(other) => {
+            if other == var {
+                to_replace.clone()
+            } else {
+                ty
+            }
+        }
+        Type::Tuple(tys) => Type::Tuple(
+            tys.into_iter()
+                .map(|ty| replace_num_var(ty, var, to_replace))
+                .collect(),
+        ),
+    }
+}
+
 fn replace_unif_var(ty: Type, var: usize, to_replace: &Type) -> Type {
     match ty {
-        Type::Num | Type::Bool | Type::String | Type::TyVar(_) => ty,
+        Type::Int | Type::Float | Type::Bool | Type::String | Type::TyVar(_) | Type::NumVar(_) => {
+            ty
+        }
         Type::UnifVar(other) => {
             if other == var {
                 to_replace.clone()
@@ -374,82 +764,194 @@ fn replace_unif_var(ty: Type, var: usize, to_replace: &Type) -> Type {
     }
 }
 
-fn unify(local_env: &LocalTypeEnv) -> Result<HashMap<usize, Type>, TypeError> {
-    let mut subst: HashMap<usize, Type> = HashMap::new();
+fn unify(local_env: &mut LocalTypeEnv) -> Result<UnifTable, TypeError> {
+    let mut table = std::mem::take(&mut local_env.table);
 
-    for (ty1, ty2) in &local_env.unification_constraints {
-        unify_types(&mut subst, ty1, ty2)?
+    for (ty1, ty2, span) in &local_env.unification_constraints {
+        unify_types(&mut table, ty1, ty2, span)?
     }
 
-    Ok(subst)
+    Ok(table)
 }
 
-fn unify_types(subst: &mut HashMap<usize, Type>, ty1: &Type, ty2: &Type) -> Result<(), TypeError> {
+fn unify_types(
+    table: &mut UnifTable,
+    ty1: &Type,
+    ty2: &Type,
+    span: &Span,
+) -> Result<(), TypeError> {
     match (ty1, ty2) {
-        (Type::Num, Type::Num) | (Type::Bool, Type::Bool) | (Type::String, Type::String) => Ok(()),
+        (Type::Int, Type::Int) | (Type::Float, Type::Float) | (Type::Bool, Type::Bool) | (Type::String, Type::String) => {
+            Ok(())
+        }
+        // An `Int` meeting a `Float` is accepted and recorded as a widening of the `Int` to
+        // `Float`. Neither `ty1` nor `ty2` is treated as the expected type (e.g. `Stream::Cond`
+        // pushes `(then_ty, else_ty, ...)` with no canonical order), so both orderings are
+        // accepted: a `Float` flowing into an `Int`-typed position (such as `Mod`'s input) is
+        // not rejected either.
+        (Type::Float, Type::Int) | (Type::Int, Type::Float) => {
+            table.record_coercion(span.clone());
+            Ok(())
+        }
         (Type::TyVar(a), Type::TyVar(b)) if a == b => Ok(()),
-        (Type::Tuple(tys1), Type::Tuple(tys2)) if tys1.len() == tys2.len() => {
+        (Type::Tuple(tys1), Type::Tuple(tys2)) => {
+            if tys1.len() != tys2.len() {
+                return Err(TypeError::TupleArityMismatch {
+                    expected: tys1.len(),
+                    found: tys2.len(),
+                    span: span.clone(),
+                });
+            }
             for (ty1, ty2) in tys1.iter().zip(tys2.iter()) {
-                unify_types(subst, ty1, ty2)?
+                unify_types(table, ty1, ty2, span)?
             }
             Ok(())
         }
+        (Type::UnifVar(a), Type::UnifVar(b)) => {
+            let (ra, rb) = (table.find(*a), table.find(*b));
+            if ra == rb {
+                return Ok(());
+            }
+
+            let (value_a, value_b) = (table.value(ra), table.value(rb));
+            let numeric = table.is_numeric(ra) || table.is_numeric(rb);
+            let root = table.union_vars(ra, rb);
+            match (value_a, value_b) {
+                (Some(ty_a), Some(ty_b)) => {
+                    if numeric {
+                        if let Some(joined) = join_numeric(&ty_a, &ty_b) {
+                            if !matches!(
+                                (&ty_a, &ty_b),
+                                (Type::Int, Type::Int) | (Type::Float, Type::Float)
+                            ) {
+                                table.record_coercion(span.clone());
+                            }
+                            table.bind(root, joined);
+                            return Ok(());
+                        }
+                    }
+                    unify_types(table, &ty_a, &ty_b, span)
+                }
+                (Some(ty), None) | (None, Some(ty)) => {
+                    table.bind(root, ty);
+                    Ok(())
+                }
+                (None, None) => Ok(()),
+            }
+        }
         (Type::UnifVar(a), ty2) => {
-            let a_type = match subst.get(a) {
-                Some(ty) => Some(ty.clone()),
+            let root = table.find(*a);
+            match table.value(root) {
+                Some(ty1) => {
+                    if table.is_numeric(root) {
+                        if let Some(joined) = join_numeric(&ty1, ty2) {
+                            if !matches!(
+                                (&ty1, ty2),
+                                (Type::Int, Type::Int) | (Type::Float, Type::Float)
+                            ) {
+                                table.record_coercion(span.clone());
+                            }
+                            table.bind(root, joined);
+                            return Ok(());
+                        }
+                    }
+                    unify_types(table, &ty1, ty2, span)
+                }
                 None => {
-                    // TODO: occurs check
-                    subst.insert(*a, ty2.clone());
-                    None
+                    let mut visited = Vec::new();
+                    if occurs_check(table, &mut visited, root, ty2) {
+                        return Err(TypeError::InfiniteType(root, ty2.clone(), span.clone()));
+                    }
+                    table.bind(root, ty2.clone());
+                    Ok(())
                 }
-            };
-            match a_type {
-                Some(ty) => unify_types(subst, &ty, ty2),
-                None => Ok(()),
             }
         }
         (ty1, Type::UnifVar(b)) => {
-            unify_types(subst, &Type::UnifVar(*b), ty1) // Swap the types to avoid having to duplicate unif var logic
+            unify_types(table, &Type::UnifVar(*b), ty1, span) // Swap the types to avoid having to duplicate unif var logic
+        }
+        _ => {
+            // Resolve both sides before reporting them: if either is a `Tuple` holding an
+            // unbound `UnifVar`, the raw clone would print an internal id ("Uniform Var: 7")
+            // instead of the type the user actually wrote.
+            let ty1 = table.resolve_deep(ty1);
+            let ty2 = table.resolve_deep(ty2);
+            Err(TypeError::CannotUnify(ty1, ty2, span.clone()))
         }
-        _ => Err(TypeError::CannotUnify(ty1.clone(), ty2.clone())),
     }
 }
 
-fn free_unif_vars(subst: &HashMap<usize, Type>, result: &mut Vec<usize>, ty: &Type) {
+// `a` and `b` must each be `Int` or `Float`. Returns their least upper bound under the
+// "`Int` widens to `Float`" ordering, or `None` if either isn't numeric.
+fn join_numeric(a: &Type, b: &Type) -> Option<Type> {
+    match (a, b) {
+        (Type::Int, Type::Int) => Some(Type::Int),
+        (Type::Float, Type::Float) => Some(Type::Float),
+        (Type::Int, Type::Float) | (Type::Float, Type::Int) => Some(Type::Float),
+        _ => None,
+    }
+}
+
+// Walks `ty` through `table`, following chains of bound `UnifVar`s, to check whether `var`
+// (already a root) appears anywhere inside it. `visited` guards against following a cycle
+// that already exists in `table` (from an earlier, unrelated unification) into an infinite loop.
+fn occurs_check(table: &mut UnifTable, visited: &mut Vec<usize>, var: usize, ty: &Type) -> bool {
     match ty {
-        Type::UnifVar(a) => match subst.get(a) {
-            None => result.push(*a),
-            Some(ty) => free_unif_vars(subst, result, ty),
-        },
-        Type::Bool | Type::Num | Type::String | Type::TyVar(_) => (),
+        Type::UnifVar(other) => {
+            let root = table.find(*other);
+            if root == var {
+                return true;
+            }
+            if visited.contains(&root) {
+                return false;
+            }
+            match table.value(root) {
+                Some(bound) => {
+                    visited.push(root);
+                    occurs_check(table, visited, var, &bound)
+                }
+                None => false,
+            }
+        }
+        Type::Int | Type::Float | Type::Bool | Type::String | Type::TyVar(_) | Type::NumVar(_) => false,
+        Type::Tuple(tys) => tys.iter().any(|ty| occurs_check(table, visited, var, ty)),
+    }
+}
+
+// Collects the ids of the unbound `UnifVar`s appearing in `ty`. `ty` must already be fully
+// resolved (via `UnifTable::resolve_deep`), so every `UnifVar` left in it is an unbound root.
+fn free_unif_vars(result: &mut Vec<usize>, ty: &Type) {
+    match ty {
+        Type::UnifVar(a) => result.push(*a),
+        Type::Bool | Type::Int | Type::Float | Type::String | Type::TyVar(_) | Type::NumVar(_) => (),
         Type::Tuple(tys) => {
             for ty in tys {
-                free_unif_vars(subst, result, ty)
+                free_unif_vars(result, ty)
             }
         }
     }
 }
 
-fn generalize(subst: &HashMap<usize, Type>, machine_ty: MachineType) -> MachineType {
+fn generalize(table: &mut UnifTable, machine_ty: MachineType) -> MachineType {
+    let input = table.resolve_deep(&machine_ty.input);
+    let output = table.resolve_deep(&machine_ty.output);
+
     let mut free_vars = Vec::new();
-    free_unif_vars(subst, &mut free_vars, &machine_ty.input);
-    free_unif_vars(subst, &mut free_vars, &machine_ty.output);
+    free_unif_vars(&mut free_vars, &input);
+    free_unif_vars(&mut free_vars, &output);
 
     let input = free_vars
         .iter()
         .enumerate()
-        .rfold(machine_ty.input, |ty, (i, var)| {
-            replace_unif_var(ty, *var, &Type::TyVar(i))
-        });
+        .rfold(input, |ty, (i, var)| replace_unif_var(ty, *var, &Type::TyVar(i)));
     let output = free_vars
         .iter()
         .enumerate()
-        .rfold(machine_ty.output, |ty, (i, var)| {
-            replace_unif_var(ty, *var, &Type::TyVar(i))
-        });
+        .rfold(output, |ty, (i, var)| replace_unif_var(ty, *var, &Type::TyVar(i)));
 
     MachineType {
         var_count: free_vars.len(),
+        numeric_var_count: 0,
         input,
         output,
     }